@@ -0,0 +1,72 @@
+use std::fmt;
+
+use parser::Span;
+
+/// The handful of ways a Lithium program can fail. Kept as a closed,
+/// specific set (rather than a free-form string) so callers - the REPL,
+/// tests, an embedding host - can match on *what* went wrong, not just
+/// read a message.
+#[derive(Debug, Clone)]
+pub enum ErrorKind {
+    UnknownMessage { name: String },
+    ArityMismatch { expected: usize, found: usize },
+    TypeMismatch { expected: String },
+    UnexpectedToken { found: String },
+    UnterminatedLambda,
+    UndeclaredVariable { name: String },
+    DivideByZero,
+    NumberOutOfRange { literal: String },
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::UnknownMessage { ref name } => write!(f, "unknown message '{}'", name),
+            ErrorKind::ArityMismatch { expected, found } => {
+                write!(f, "expected {} argument(s), found {}", expected, found)
+            },
+            ErrorKind::TypeMismatch { ref expected } => write!(f, "type mismatch: expected {}", expected),
+            ErrorKind::UnexpectedToken { ref found } => write!(f, "unexpected {}", found),
+            ErrorKind::UnterminatedLambda => write!(f, "unterminated lambda, expected a closing ']'"),
+            ErrorKind::UndeclaredVariable { ref name } => {
+                write!(f, "cannot assign to undeclared variable '{}' (did you mean 'def {}'?)", name, name)
+            },
+            ErrorKind::DivideByZero => write!(f, "division by zero"),
+            ErrorKind::NumberOutOfRange { ref literal } => {
+                write!(f, "number literal '{}' is too large to represent", literal)
+            },
+        }
+    }
+}
+
+/// A structured interpreter failure that remembers where in the source it
+/// happened, so it can be reported with the offending line and a caret
+/// instead of taking the whole process down with it.
+#[derive(Debug, Clone)]
+pub struct LithiumError {
+    pub kind: ErrorKind,
+    pub span: Span,
+}
+
+impl LithiumError {
+    pub fn new(kind: ErrorKind, span: Span) -> LithiumError {
+        LithiumError { kind, span }
+    }
+
+    // Renders the error against the source it came from, e.g.:
+    //
+    //   unknown message 'sqrt' (line 2, column 5)
+    //   5 sqrt
+    //       ^
+    pub fn render(&self, source: &str) -> String {
+        let line = source.lines().nth(self.span.line.saturating_sub(1)).unwrap_or("");
+        let caret = format!("{}^", " ".repeat(self.span.column.saturating_sub(1)));
+        format!("{} (line {}, column {})\n{}\n{}", self.kind, self.span.line, self.span.column, line, caret)
+    }
+}
+
+impl fmt::Display for LithiumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
@@ -1,9 +1,11 @@
-use std::rc::Rc;
 use std::cell::RefCell;
-use std::fmt::Debug;
+use std::rc::Rc;
 
-use parser::{Target, Message, Expression, Block, Statement};
-use object::{Object, ObjectRef, ObjectPool, Metadata};
+use parser::{Target, Message, Expression, Block, Statement, Span};
+use object::{Object, ObjectRef, ObjectPool, Metadata, NumberValue, EvaluatedMessage, NormalObject, SendOutcome};
+use native::{NativeObject, NativeRegistry};
+use error::{LithiumError, ErrorKind};
+use vm;
 
 #[derive(Debug)]
 struct Signature {
@@ -14,12 +16,30 @@ impl Signature {
     fn is_message_valid(&self, message: &EvaluatedMessage) -> bool {
         if message.arguments.len() != self.parameters.len() { return false; }
         for parameter in &self.parameters {
-            if !message.arguments.iter().any(|&(ref name, _)| name == parameter) {
+            if !message.arguments.iter().any(|(name, _)| name == parameter) {
                 return false
             }
         }
         true
     }
+
+    fn check(&self, message: &EvaluatedMessage) -> Result<(), LithiumError> {
+        if self.is_message_valid(message) {
+            Ok(())
+        } else {
+            Err(LithiumError::new(
+                ErrorKind::ArityMismatch { expected: self.parameters.len(), found: message.arguments.len() },
+                message.span
+            ))
+        }
+    }
+}
+
+fn required_argument(message: &EvaluatedMessage, name: &str) -> Result<ObjectRef, LithiumError> {
+    message.argument(name).ok_or_else(|| LithiumError::new(
+        ErrorKind::TypeMismatch { expected: format!("a '{}:' argument", name) },
+        message.span
+    ))
 }
 
 #[derive(Debug)]
@@ -29,207 +49,565 @@ struct Lambda {
 }
 
 impl Lambda {
-    fn call_with_captured_context(&self, program: &mut Program) -> ObjectRef {
+    fn call_with_context(&self, pool: &mut ObjectPool) -> Result<ObjectRef, LithiumError> {
         eval_block(pool, self.parent_scope, &self.body)
     }
-
-    // Context given, such as when we are evaluating a method that has bubbled
-    // up from a child object.
-    fn call_with_context(&self, context: ObjectRef) -> ObjectRef {
-        eval_block(context, &self.body)
-    }
 }
 
 impl Object for Lambda {
-    fn send(&mut self, pool: &mut ObjectPool, _target: ObjectRef, message: EvaluatedMessage) -> ObjectRef {
-        match message.name {
-            ref m if m == "call" => self.call_with_captured_context(pool),
-            _ => panic!("Unknown message sent to lambda")
+    fn send(&mut self, pool: &mut ObjectPool, target: ObjectRef, message: EvaluatedMessage) -> Result<SendOutcome, LithiumError> {
+        match message.name.as_str() {
+            "call" => self.call_with_context(pool).map(SendOutcome::Value),
+            "println" => {
+                println!("<lambda>");
+                Ok(SendOutcome::Value(Void::new_reference(pool)))
+            }
+            // Falls back to any native function registered for this name,
+            // offering `target` up as the implicit receiver argument - see
+            // `ObjectPool::delegate_to_native`.
+            _ => Ok(pool.delegate_to_native(target, message))
         }
     }
 
-    fn define(&mut self, _name: String, _value: ObjectRef) -> ObjectRef {
-        panic!("Cannot extend native object Lambda");
+    fn define(&mut self, _name: String, _value: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(
+            ErrorKind::TypeMismatch { expected: "an object that can be extended (a lambda cannot)".to_string() },
+            Span::start()
+        ))
     }
-}
 
-impl ObjectRef {
-    fn new(object: Box<Object>) -> ObjectRef {
-        ObjectRef{
-            object: Rc::new(RefCell::new(object)),
-            metadata: Metadata::None
-        }
+    fn set(&mut self, _pool: &mut ObjectPool, name: String, _value: ObjectRef, span: Span) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(ErrorKind::UndeclaredVariable { name }, span))
     }
+}
 
-    fn new_with_metadata(object: Box<Object>, metadata: Metadata) -> ObjectRef {
-        ObjectRef{
-            object: Rc::new(RefCell::new(object)),
-            metadata: metadata
-        }
+#[derive(Debug)]
+pub(crate) struct Void;
+
+impl Void {
+    pub(crate) fn new_reference(pool: &mut ObjectPool) -> ObjectRef {
+        pool.insert(Box::new(Void))
     }
 }
 
-impl Object for ObjectRef {
-    fn send(&mut self, target: ObjectRef, message: EvaluatedMessage) -> ObjectRef {
-        self.object.borrow_mut().send(target, message)
+impl Object for Void {
+    fn send(&mut self, _pool: &mut ObjectPool, target: ObjectRef, _message: EvaluatedMessage) -> Result<SendOutcome, LithiumError> {
+        Ok(SendOutcome::Value(target))
     }
 
-    fn define(&mut self, name: String, value: ObjectRef) -> ObjectRef {
-        self.object.borrow_mut().define(name, value)
+    fn define(&mut self, _name: String, _value: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(
+            ErrorKind::TypeMismatch { expected: "an object that can be extended (void cannot)".to_string() },
+            Span::start()
+        ))
+    }
+
+    fn set(&mut self, _pool: &mut ObjectPool, name: String, _value: ObjectRef, span: Span) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(ErrorKind::UndeclaredVariable { name }, span))
     }
 }
 
 #[derive(Debug)]
-struct RootObject { }
+pub(crate) struct Number;
 
-impl Object for RootObject {
-    fn send(&mut self, _target: ObjectRef, message: EvaluatedMessage) -> ObjectRef {
-        match message {
-            _ => panic!("Unknown root message")
-        }
+impl Number {
+    pub(crate) fn new_reference(pool: &mut ObjectPool, literal: &str, span: Span) -> Result<ObjectRef, LithiumError> {
+        Ok(Number::new_from_value(pool, NumberValue::parse(literal, span)?))
     }
 
-    fn define(&mut self, _name: String, _value: ObjectRef) -> ObjectRef {
-        panic!("Attempt to define on the root scope. This is Evil, cut it out.")
+    pub(crate) fn new_from_value(pool: &mut ObjectPool, value: NumberValue) -> ObjectRef {
+        pool.insert_with_metadata(Box::new(Number), Metadata::NumericValue(value))
     }
 }
 
-#[derive(Debug, Clone)]
-struct Void { } 
-
-impl Void {
-    fn new_reference() -> ObjectRef {
-        ObjectRef::new(Box::new(Void{}))
+fn numeric_value(pool: &ObjectPool, reference: ObjectRef, message: &EvaluatedMessage) -> Result<NumberValue, LithiumError> {
+    match *pool.metadata(reference) {
+        Metadata::NumericValue(value) => Ok(value),
+        _ => Err(LithiumError::new(ErrorKind::TypeMismatch { expected: "a number".to_string() }, message.span))
     }
 }
 
-impl Object for Void {
-    fn send(&mut self, target: ObjectRef, _message: EvaluatedMessage) -> ObjectRef {
-        target.clone()
-    }
-
-    fn define(&mut self, _name: String, _value: ObjectRef) -> ObjectRef {
-        panic!("You have stared into the void");
+// `(an, ad, bn, bd) -> (numerator, denominator)`, carried in `i128` - see
+// `arithmetic` for why.
+type FractionOp = fn(i128, i128, i128, i128) -> (i128, i128);
+
+// Coerces a pair of numbers for an arithmetic message: if either side is a
+// `Float` the whole operation runs in floating point, otherwise both sides
+// stay exact and `op` is left to combine their `(numerator, denominator)`
+// fractions (which `NumberValue::reduce_wide` then puts back in lowest
+// terms, falling back to `Float` only if the reduced result still doesn't
+// fit in an `i64`). The components are widened to `i128` before `fraction_op`
+// runs: cross-multiplying two `i64` fractions can briefly exceed `i64::MAX`
+// even when the final, reduced result wouldn't - `i128` has enough headroom
+// that the cross products of two `i64::MAX`-sized fractions never overflow.
+fn arithmetic<F>(a: NumberValue, b: NumberValue, float_op: F, fraction_op: FractionOp) -> NumberValue
+    where F: Fn(f64, f64) -> f64
+{
+    match (a, b) {
+        (NumberValue::Float(_), _) | (_, NumberValue::Float(_)) => NumberValue::Float(float_op(a.as_f64(), b.as_f64())),
+        _ => {
+            let (an, ad) = a.as_fraction();
+            let (bn, bd) = b.as_fraction();
+            let (numerator, denominator) = fraction_op(an as i128, ad as i128, bn as i128, bd as i128);
+            NumberValue::reduce_wide(numerator, denominator)
+        }
     }
 }
 
-#[derive(Debug)]
-struct Number { }
+impl Object for Number {
+    fn send(&mut self, pool: &mut ObjectPool, target: ObjectRef, message: EvaluatedMessage) -> Result<SendOutcome, LithiumError> {
+        let value = numeric_value(pool, target, &message)?;
+
+        match message.name.as_str() {
+            "call" => Ok(SendOutcome::Value(target)),
+            "println" => {
+                println!("{}", value);
+                Ok(SendOutcome::Value(Void::new_reference(pool)))
+            },
+            "add" => {
+                Signature { parameters: vec!["to".to_string()] }.check(&message)?;
+                let other = numeric_value(pool, required_argument(&message, "to")?, &message)?;
+                let sum = arithmetic(value, other, |a, b| a + b, |an, ad, bn, bd| {
+                    (an * bd + bn * ad, ad * bd)
+                });
+                Ok(SendOutcome::Value(Number::new_from_value(pool, sum)))
+            }
+            "subtract" => {
+                Signature { parameters: vec!["to".to_string()] }.check(&message)?;
+                let other = numeric_value(pool, required_argument(&message, "to")?, &message)?;
+                let difference = arithmetic(value, other, |a, b| a - b, |an, ad, bn, bd| {
+                    (an * bd - bn * ad, ad * bd)
+                });
+                Ok(SendOutcome::Value(Number::new_from_value(pool, difference)))
+            }
+            "multiply" => {
+                Signature { parameters: vec!["to".to_string()] }.check(&message)?;
+                let other = numeric_value(pool, required_argument(&message, "to")?, &message)?;
+                let product = arithmetic(value, other, |a, b| a * b, |an, ad, bn, bd| (an * bn, ad * bd));
+                Ok(SendOutcome::Value(Number::new_from_value(pool, product)))
+            }
+            "divide" => {
+                Signature { parameters: vec!["to".to_string()] }.check(&message)?;
+                let other = numeric_value(pool, required_argument(&message, "to")?, &message)?;
+                if other.is_zero() {
+                    return Err(LithiumError::new(ErrorKind::DivideByZero, message.span));
+                }
+                let quotient = arithmetic(value, other, |a, b| a / b, |an, ad, bn, bd| (an * bd, ad * bn));
+                Ok(SendOutcome::Value(Number::new_from_value(pool, quotient)))
+            }
+            "equals" => {
+                Signature { parameters: vec!["to".to_string()] }.check(&message)?;
+                let other = numeric_value(pool, required_argument(&message, "to")?, &message)?;
+                let equal = match (value, other) {
+                    (NumberValue::Float(_), _) | (_, NumberValue::Float(_)) => value.as_f64() == other.as_f64(),
+                    // Both sides are already in lowest terms (see `NumberValue::reduce`),
+                    // so equal values always share the same `(numerator, denominator)` -
+                    // no cross-multiplication (and so no overflow risk) needed.
+                    _ => value.as_fraction() == other.as_fraction()
+                };
+                Ok(SendOutcome::Value(Boolean::new_reference(pool, equal)))
+            }
+            "lessThan" => {
+                Signature { parameters: vec!["to".to_string()] }.check(&message)?;
+                let other = numeric_value(pool, required_argument(&message, "to")?, &message)?;
+                let less = match (value, other) {
+                    (NumberValue::Float(_), _) | (_, NumberValue::Float(_)) => value.as_f64() < other.as_f64(),
+                    _ => {
+                        // Denominators are always positive (see `NumberValue::reduce`),
+                        // so cross-multiplying preserves ordering. Widened to `i128`
+                        // rather than compared as a lossy float - two `i64`-sized
+                        // fractions can be distinct while rounding to the same `f64`,
+                        // which would otherwise silently answer `lessThan` wrong instead
+                        // of just overflowing; `i128` has enough headroom that this
+                        // cross product never overflows.
+                        let (an, ad) = value.as_fraction();
+                        let (bn, bd) = other.as_fraction();
+                        (an as i128) * (bd as i128) < (bn as i128) * (ad as i128)
+                    }
+                };
+                Ok(SendOutcome::Value(Boolean::new_reference(pool, less)))
+            }
+            // Falls back to any native function registered for this name,
+            // offering `target` up as the implicit receiver argument - see
+            // `ObjectPool::delegate_to_native`.
+            _ => Ok(pool.delegate_to_native(target, message))
+        }
+    }
 
-impl Number {
-    fn new_reference(digits: &str) -> ObjectRef {
-        Number::new_from_value(digits.parse::<i64>().unwrap())
+    fn define(&mut self, _name: String, _value: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(
+            ErrorKind::TypeMismatch { expected: "an object that can be extended (a number cannot)".to_string() },
+            Span::start()
+        ))
     }
 
-    fn new_from_value(value: i64) -> ObjectRef {
-        ObjectRef::new_with_metadata(
-            Box::new(Number{}),
-            Metadata::NumericValue(value)
-        )
+    fn set(&mut self, _pool: &mut ObjectPool, name: String, _value: ObjectRef, span: Span) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(ErrorKind::UndeclaredVariable { name }, span))
     }
 }
 
-fn get_argument(target: &str, arguments: Vec<(String, ObjectRef)>) -> ObjectRef {
-    arguments.iter().filter(|&&(ref name, _)| name == target).map(|&(_, ref value)| value.clone())
-        .next().unwrap()
+#[derive(Debug)]
+pub(crate) struct Boolean;
+
+impl Boolean {
+    pub(crate) fn new_reference(pool: &mut ObjectPool, value: bool) -> ObjectRef {
+        pool.insert_with_metadata(Box::new(Boolean), Metadata::BooleanValue(value))
+    }
 }
 
-impl Object for Number {
-    fn send(&mut self, target: ObjectRef, message: EvaluatedMessage) -> ObjectRef {
-        let numeric_value = match target.metadata {
-            Metadata::NumericValue(val) => val,
-            _ => panic!("Number type has no numeric value metadata")
+impl Object for Boolean {
+    fn send(&mut self, pool: &mut ObjectPool, target: ObjectRef, message: EvaluatedMessage) -> Result<SendOutcome, LithiumError> {
+        let value = match *pool.metadata(target) {
+            Metadata::BooleanValue(val) => val,
+            _ => return Err(LithiumError::new(ErrorKind::TypeMismatch { expected: "a boolean".to_string() }, message.span))
         };
 
-        let number_add_signature: Signature = Signature {
-            parameters: vec!["to".to_string()]
-        };
+        match message.name.as_str() {
+            "call" => Ok(SendOutcome::Value(target)),
+            "println" => {
+                println!("{}", value);
+                Ok(SendOutcome::Value(Void::new_reference(pool)))
+            }
+            // `a then ifTrue: [...] ifFalse: [...]` - the branch matching
+            // `value` is `call`ed, the other is never even evaluated.
+            "then" => {
+                let if_true_if_false = Signature { parameters: vec!["ifTrue".to_string(), "ifFalse".to_string()] };
+                let if_true_only = Signature { parameters: vec!["ifTrue".to_string()] };
+
+                let branch = if if_true_if_false.is_message_valid(&message) {
+                    message.argument(if value { "ifTrue" } else { "ifFalse" })
+                } else if if_true_only.is_message_valid(&message) {
+                    if value { message.argument("ifTrue") } else { None }
+                } else {
+                    return Err(LithiumError::new(
+                        ErrorKind::ArityMismatch { expected: 2, found: message.arguments.len() },
+                        message.span
+                    ));
+                };
 
-        match &message.name {
-            m if m == "println" => {
-                println!("{}", numeric_value);
-                Void::new_reference()
-            },
-            m if m == "add" => {
-                if !number_add_signature.is_message_valid(&message) {
-                    panic!("Invalid signature for Number#add")
+                match branch {
+                    Some(block) => {
+                        let call = EvaluatedMessage { name: "call".to_string(), arguments: Vec::new(), span: message.span };
+                        Ok(SendOutcome::Delegate(block, call))
+                    }
+                    None => Ok(SendOutcome::Value(Void::new_reference(pool)))
                 }
-                let other = get_argument("to", message.arguments);
-                let sum = match other.metadata {
-                    Metadata::NumericValue(val) => numeric_value + val,
-                    _ => panic!("Number#add must be called with a number")
-                };
-                Number::new_from_value(sum)
             }
-            _ => { panic!("Because it got that way") }
+            // Falls back to any native function registered for this name,
+            // offering `target` up as the implicit receiver argument - see
+            // `ObjectPool::delegate_to_native`.
+            _ => Ok(pool.delegate_to_native(target, message))
         }
     }
 
-    fn define(&mut self, _name: String, _value: ObjectRef) -> ObjectRef {
-        panic!("Cannot extend native object Number");
+    fn define(&mut self, _name: String, _value: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(
+            ErrorKind::TypeMismatch { expected: "an object that can be extended (a boolean cannot)".to_string() },
+            Span::start()
+        ))
+    }
+
+    fn set(&mut self, _pool: &mut ObjectPool, name: String, _value: ObjectRef, span: Span) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(ErrorKind::UndeclaredVariable { name }, span))
     }
 }
 
 pub struct Program {
-    pool: ObjectPool
+    pool: ObjectPool,
+    // The scope top-level entries are evaluated into. Kept around (rather
+    // than rebuilt per call) so that a REPL session's `def`s accumulate
+    // across entries instead of being discarded with their scope.
+    scope: ObjectRef,
+    natives: Rc<RefCell<NativeRegistry>>,
 }
 
 impl Program {
-    pub fn eval(&mut self, block: Block) {
-        let root = Box::new(RootObject{});
-        let root_ref = ObjectRef::new(root);
-        self.eval_block(root_ref, &block);
+    pub fn new() -> Program {
+        let mut pool = ObjectPool::new();
+        let natives = Rc::new(RefCell::new(NativeRegistry::new()));
+        let native_object = pool.insert(Box::new(NativeObject::new(natives.clone())));
+        pool.set_native(native_object);
+        let scope = pool.insert(Box::new(NormalObject::extending(native_object)));
+        Program { pool, scope, natives }
     }
 
-    fn eval_block(&mut self, parent_scope: ObjectRef, block: &Block) -> ObjectRef {
-        let scope = ObjectRef::new(Box::new(NormalObject::extending(parent_scope)));
-        let mut statements = block.statements.iter();
-        let mut last = self.eval_statement(scope, statements.next().expect("Cannot evaluate empty block"));
-        for statement in statements {
-            last = self.eval_statement(scope, statement);
+    // Lets host Rust code surface its own functions to scripts, e.g.
+    // `program.register_fn("sqrt", 1, |pool, args| ...)`. Registered names
+    // are reachable both as bare identifiers from any scope (every scope's
+    // prototype chain bottoms out at the `NativeObject` backed by this
+    // registry) and as ordinary sends to a value (`5 sqrt`), since `Number`
+    // and friends fall back to the same registry for a message they don't
+    // recognize - see `ObjectPool::delegate_to_native`. In the latter case
+    // the arity counts the receiver as an argument, so `sqrt`'s handler
+    // here is registered at arity 1 even though `5 sqrt` passes no named
+    // arguments of its own.
+    pub fn register_fn<F>(&mut self, name: &str, arity: usize, handler: F)
+        where F: Fn(&mut ObjectPool, &[ObjectRef]) -> ObjectRef + 'static
+    {
+        self.natives.borrow_mut().register(name, arity, handler);
+    }
+
+    // Compiles `block` to bytecode and runs it on the stack VM. This is the
+    // normal entry point; `eval_tree` below walks the AST directly instead
+    // and exists as a fallback / reference implementation for the compiler.
+    pub fn eval(&mut self, block: Block) -> Result<ObjectRef, LithiumError> {
+        let chunks = Rc::new(vm::Compiler::compile(&block)?);
+        vm::Vm::new(&mut self.pool, chunks).run_in_scope(0, self.scope)
+    }
+
+    pub fn eval_tree(&mut self, block: Block) -> Result<ObjectRef, LithiumError> {
+        eval_statements(&mut self.pool, self.scope, &block)
+    }
+
+    // Sends `println` to a value so a caller (the REPL) can show the
+    // result of an entry the same way a script would with an explicit
+    // `println` message.
+    pub fn print(&mut self, value: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        let message = EvaluatedMessage { name: "println".to_string(), arguments: Vec::new(), span: Span::start() };
+        self.pool.send(value, message)
+    }
+}
+
+fn eval_statements(pool: &mut ObjectPool, scope: ObjectRef, block: &Block) -> Result<ObjectRef, LithiumError> {
+    let mut last = Void::new_reference(pool);
+    for statement in &block.statements {
+        last = eval_statement(pool, scope, statement)?;
+    }
+    Ok(last)
+}
+
+fn eval_block(pool: &mut ObjectPool, parent_scope: ObjectRef, block: &Block) -> Result<ObjectRef, LithiumError> {
+    let scope = pool.insert(Box::new(NormalObject::extending(parent_scope)));
+    eval_statements(pool, scope, block)
+}
+
+fn eval_statement(pool: &mut ObjectPool, scope: ObjectRef, statement: &Statement) -> Result<ObjectRef, LithiumError> {
+    match *statement {
+        Statement::Definition(ref definition) => {
+            let value = eval_expression(pool, scope, &definition.value)?;
+            pool.define(scope, definition.target.clone(), value)
         }
+        Statement::Assignment(ref assignment) => {
+            let value = eval_expression(pool, scope, &assignment.value)?;
+            pool.set(scope, assignment.target.clone(), value, assignment.span)
+        }
+        Statement::Expression(ref expression) => eval_expression(pool, scope, expression)
     }
+}
 
-    fn eval_statement(&mut self, mut scope: ObjectRef, statement: &Statement) -> ObjectRef {
-        match statement {
-            &Statement::Definition(ref definition) => {
-                let value = self.eval_expression(scope, &definition.value);
-                scope.define(definition.target.clone(), value)
+fn eval_message(pool: &mut ObjectPool, scope: ObjectRef, message: &Message) -> Result<EvaluatedMessage, LithiumError> {
+    let mut arguments = Vec::with_capacity(message.arguments.len());
+    for argument in &message.arguments {
+        let evaluated = eval_expression(pool, scope, &argument.value)?;
+        arguments.push((argument.name.clone(), evaluated));
+    }
+    Ok(EvaluatedMessage { name: message.name.clone(), arguments, span: message.span })
+}
+
+fn eval_expression(pool: &mut ObjectPool, scope: ObjectRef, expression: &Expression) -> Result<ObjectRef, LithiumError> {
+    match *expression {
+        Expression::Send(ref send) => {
+            let mut target = match send.target {
+                Target::Identifier(ref ident) => {
+                    let message = EvaluatedMessage { name: ident.to_string(), arguments: Vec::new(), span: send.span };
+                    pool.send(scope, message)?
+                },
+                Target::Number(ref num) => Number::new_reference(pool, num, send.span)?,
+                Target::Expression(ref target_expression) => {
+                    eval_expression(pool, scope, target_expression)?
+                }
+            };
+            for message in &send.messages {
+                let evaluated_message = eval_message(pool, scope, message)?;
+                target = pool.send(target, evaluated_message)?;
             }
-            &Statement::Expression(ref expression) => self.eval_expression(scope, &expression)
+            Ok(target)
+        },
+        Expression::Number(ref digits, span) => Number::new_reference(pool, digits, span),
+        Expression::Lambda(ref block) => {
+            Ok(pool.insert(Box::new(Lambda { parent_scope: scope, body: block.clone() })))
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::{tokenize, parse_program};
+
+    fn run_vm(source: &str) -> Result<ObjectRef, LithiumError> {
+        let block = parse_program(tokenize(source.to_string())?)?;
+        Program::new().eval(block)
+    }
 
-    fn eval_message(&mut self, scope: ObjectRef, message: &Message) -> EvaluatedMessage {
-        let bindings = message.arguments.iter().map(|arg| {
-            let evaluated = self.eval_expression(scope, &arg.value);
-            (arg.name.clone(), evaluated)
-        }).collect();
-        EvaluatedMessage { name: message.name.clone(), arguments: bindings }
+    fn run_tree(source: &str) -> Result<ObjectRef, LithiumError> {
+        let block = parse_program(tokenize(source.to_string())?)?;
+        Program::new().eval_tree(block)
     }
 
-    fn eval_expression(&mut self, mut scope: ObjectRef, expression: &Expression) -> ObjectRef {
-        match expression {
-            &Expression::Send(ref send) => {
-                let mut target = match &send.target {
-                    &Target::Identifier(ref ident) => {
-                        let message = EvaluatedMessage{name: ident.to_string(), arguments: Vec::new() };
-                        scope.send(scope, message)
-                    },
-                    &Target::Number(ref num) => Number::new_reference(num),
-                    &Target::Expression(ref target_expression) => {
-                        self.eval_expression(scope, target_expression)
-                    }
-                };
-                send.messages.iter().map(|message| {
-                    let target_clone = target.clone();
-                    target.send(target_clone, self.eval_message(scope, &message))
-                }).last().expect("Uh oh, cannot determine the value of an empty expression")
-            },
-            &Expression::Number(ref digits) => Number::new_reference(digits),
-            &Expression::Lambda(ref block) => {
-                ObjectRef::new(Box::new(Lambda{parent_scope: scope.clone(), body: block.clone()}))
-            }
+    // A named function reading a variable from its own defining scope used
+    // to panic with "send to an object that is already handling a message" -
+    // looking up `x` re-entered the outer scope object while it was still
+    // checked out of the pool for the call that looked up `f` itself.
+    #[test]
+    fn lambda_reads_a_variable_from_its_defining_scope() {
+        assert!(run_vm("def x 5\ndef f [x println]\nf call").is_ok());
+        assert!(run_tree("def x 5\ndef f [x println]\nf call").is_ok());
+    }
+
+    // Same underlying bug, via one function calling another rather than a
+    // variable lookup.
+    #[test]
+    fn one_function_calling_another_does_not_panic() {
+        assert!(run_vm("def helper [1 println]\ndef main [helper call]\nmain call").is_ok());
+        assert!(run_tree("def helper [1 println]\ndef main [helper call]\nmain call").is_ok());
+    }
+
+    // A native fn registered at arity 1 used to be unreachable as `4 sqrt` -
+    // `Number` had nowhere to forward a message it didn't recognize, so only
+    // a niladic global constant (reached via the scope chain, not a send to
+    // a value) ever worked.
+    #[test]
+    fn native_function_is_reachable_as_an_ordinary_send() {
+        let mut program = Program::new();
+        program.register_fn("sqrt", 1, |pool, args| {
+            let root = match *pool.metadata(args[0]) {
+                Metadata::NumericValue(value) => value.as_f64().sqrt(),
+                _ => unreachable!("sqrt's only argument should always be a Number"),
+            };
+            Number::new_from_value(pool, NumberValue::Float(root))
+        });
+
+        let block = parse_program(tokenize("4 sqrt".to_string()).unwrap()).unwrap();
+        let result = program.eval(block).unwrap();
+        match *program.pool.metadata(result) {
+            Metadata::NumericValue(NumberValue::Float(root)) => assert!((root - 2.0).abs() < 1e-9),
+            ref other => panic!("expected a float, got {:?}", other),
+        }
+    }
+
+    // Typing a lambda body across several lines - the REPL's whole reason
+    // for detecting "incomplete input" by balanced brackets rather than by
+    // newline - used to fail to parse: a block's contents could only start
+    // with a statement on the same line as the opening '[', and a blank
+    // line between two statements (or right before the closing ']') broke
+    // the same way.
+    #[test]
+    fn a_lambda_body_can_be_typed_across_several_lines() {
+        let source = "def f [\n1 println\n2 println\n]\nf call";
+        assert!(run_vm(source).is_ok());
+        assert!(run_tree(source).is_ok());
+
+        let blank_lines = "def f [\n\n1 println\n\n2 println\n\n]\nf call";
+        assert!(run_vm(blank_lines).is_ok());
+        assert!(run_tree(blank_lines).is_ok());
+    }
+
+    // A rational literal with a zero denominator used to sail through
+    // `NumberValue::reduce` unchecked - `1/0 println` printed `1/0` instead
+    // of erroring, and arithmetic on it produced silently wrong results,
+    // even though the runtime `divide` message already guards the same
+    // case with `DivideByZero`.
+    #[test]
+    fn a_rational_literal_with_a_zero_denominator_is_a_divide_by_zero_error() {
+        let source = "1/0 println";
+        match run_vm(source) {
+            Err(LithiumError { kind: ErrorKind::DivideByZero, .. }) => {},
+            other => panic!("expected DivideByZero, got {:?}", other),
+        }
+        match run_tree(source) {
+            Err(LithiumError { kind: ErrorKind::DivideByZero, .. }) => {},
+            other => panic!("expected DivideByZero, got {:?}", other),
+        }
+    }
+
+    // `then`'s untaken branch must never be evaluated - otherwise an
+    // `ifFalse:` guarding a division can't protect the division it guards.
+    // Checked by making the untaken branch a `divide to: 0`: if `then`
+    // evaluated it anyway, these would fail with `DivideByZero` instead
+    // of succeeding.
+    #[test]
+    fn then_only_evaluates_the_taken_branch() {
+        let if_true_taken = "(1 equals to: 1) then ifTrue: [1 println] ifFalse: [1 divide to: 0]";
+        assert!(run_vm(if_true_taken).is_ok());
+        assert!(run_tree(if_true_taken).is_ok());
+
+        let if_false_taken = "(1 equals to: 2) then ifTrue: [1 divide to: 0] ifFalse: [1 println]";
+        assert!(run_vm(if_false_taken).is_ok());
+        assert!(run_tree(if_false_taken).is_ok());
+    }
+
+    // The one-armed form (`then ifTrue:` with no `ifFalse:`) must likewise
+    // skip the body entirely when the condition is false, rather than
+    // erroring for a missing `ifFalse` argument.
+    #[test]
+    fn then_with_only_an_if_true_branch_is_a_no_op_when_false() {
+        let source = "(1 equals to: 2) then ifTrue: [1 divide to: 0]";
+        assert!(run_vm(source).is_ok());
+        assert!(run_tree(source).is_ok());
+    }
+
+    // `def` inside a lambda body must shadow locally, leaving the
+    // outer binding of the same name untouched once the lambda returns -
+    // distinct from `set`, which reaches out to mutate it (see the next
+    // test).
+    #[test]
+    fn define_inside_a_lambda_shadows_without_leaking_out() {
+        let source = "def x 1\ndef f [\ndef x 2\n]\nf call\nx add to: 0";
+        assert_eq!(eval_number(source, Program::eval), NumberValue::Integer(1));
+        assert_eq!(eval_number(source, Program::eval_tree), NumberValue::Integer(1));
+    }
+
+    // `set` inside a lambda body walks the scope chain to the nearest
+    // existing binding and mutates it in place, rather than shadowing
+    // locally like `define`.
+    #[test]
+    fn set_inside_a_lambda_mutates_the_enclosing_binding() {
+        let source = "def x 1\ndef f [\nset x 2\n]\nf call\nx add to: 0";
+        assert_eq!(eval_number(source, Program::eval), NumberValue::Integer(2));
+        assert_eq!(eval_number(source, Program::eval_tree), NumberValue::Integer(2));
+    }
+
+    fn eval_number(source: &str, eval: fn(&mut Program, Block) -> Result<ObjectRef, LithiumError>) -> NumberValue {
+        let mut program = Program::new();
+        let block = parse_program(tokenize(source.to_string()).unwrap()).unwrap();
+        let reference = eval(&mut program, block).unwrap_or_else(|err| panic!("expected Ok, got {:?}", err));
+        match *program.pool.metadata(reference) {
+            Metadata::NumericValue(value) => value,
+            ref other => panic!("expected a number, got {:?}", other),
+        }
+    }
+
+    // An integer literal too large for `i64` used to panic via
+    // `.expect("malformed integer literal")` instead of reporting a
+    // `LithiumError` like every other user-facing failure.
+    #[test]
+    fn oversized_integer_literal_is_a_lithium_error_not_a_panic() {
+        let huge = "99999999999999999999999999 println";
+        match run_vm(huge) {
+            Err(LithiumError { kind: ErrorKind::NumberOutOfRange { .. }, .. }) => {},
+            other => panic!("expected NumberOutOfRange, got {:?}", other),
+        }
+        match run_tree(huge) {
+            Err(LithiumError { kind: ErrorKind::NumberOutOfRange { .. }, .. }) => {},
+            other => panic!("expected NumberOutOfRange, got {:?}", other),
+        }
+    }
+
+    // `i64::MAX add: 1` used to panic with "attempt to add with overflow"
+    // instead of following the numeric tower's usual policy of widening to
+    // a `Float` whenever exact arithmetic can't represent the result.
+    #[test]
+    fn integer_overflow_widens_to_a_float_instead_of_panicking() {
+        let source = "9223372036854775807 add to: 1";
+        assert!(run_tree(source).is_ok());
+
+        let mut program = Program::new();
+        let block = parse_program(tokenize(source.to_string()).unwrap()).unwrap();
+        let result = program.eval(block).unwrap();
+        match *program.pool.metadata(result) {
+            Metadata::NumericValue(NumberValue::Float(sum)) => assert!((sum - 9223372036854775808.0).abs() < 1.0),
+            ref other => panic!("expected a float, got {:?}", other),
         }
     }
 }
@@ -1,17 +1,110 @@
+// The tree-walking evaluator (`Program::eval_tree` and everything it calls)
+// and the native-function registration API are kept as reference
+// implementations / embedding surface that this binary doesn't exercise
+// itself - only a host embedding the crate, or someone debugging the VM
+// against it, calls them.
+#![allow(dead_code)]
+
 mod object;
 mod parser;
+mod error;
 mod eval;
+mod native;
+mod vm;
 
-use parser::{tokenize, parse_program};
+use parser::{tokenize, parse_program, TokenKind};
 use eval::Program;
+use error::LithiumError;
+
+use std::io::{self, BufRead, Read, Write};
 
-use std::io::{self, Read};
+// No `isatty` in stable std without pulling in a crate, and there is no
+// Cargo.toml to add one to - so call straight through to libc, the way a
+// lot of small interpreters bootstrap this check.
+extern "C" {
+    fn isatty(fd: i32) -> i32;
+}
+
+fn stdin_is_tty() -> bool {
+    unsafe { isatty(0) != 0 }
+}
 
 fn main() {
-    let mut program = String::new();
-    io::stdin().read_to_string(&mut program).unwrap();
-    let tokens = tokenize(program);
-    let parsed = parse_program(tokens);
-    // println!("{:#?}", parsed);
-    Program::new().eval(parsed);
+    let repl_requested = std::env::args().skip(1).any(|arg| arg == "--repl");
+    if repl_requested || stdin_is_tty() {
+        run_repl();
+    } else {
+        run_script();
+    }
+}
+
+fn run_script() {
+    let mut source = String::new();
+    io::stdin().read_to_string(&mut source).unwrap();
+    if let Err(error) = run(&source, &mut Program::new()) {
+        eprintln!("{}", error.render(&source));
+        std::process::exit(1);
+    }
+}
+
+fn run(source: &str, program: &mut Program) -> Result<(), LithiumError> {
+    let tokens = tokenize(source.to_string())?;
+    let parsed = parse_program(tokens)?;
+    let value = program.eval(parsed)?;
+    program.print(value)?;
+    Ok(())
+}
+
+// Reads one logical entry at a time from stdin, evaluating each against a
+// single long-lived `Program` so that `def`s persist across entries. An
+// entry isn't handed to the parser until its brackets balance, so a lambda
+// or parenthesized expression can be split across as many lines as the
+// user likes.
+fn run_repl() {
+    let mut program = Program::new();
+    let stdin = io::stdin();
+    let mut entry = String::new();
+
+    loop {
+        print!("{}", if entry.is_empty() { "lithium> " } else { "...... " });
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            println!();
+            return;
+        }
+        entry.push_str(&line);
+
+        if !brackets_balanced(&entry) {
+            continue;
+        }
+
+        let source = std::mem::take(&mut entry);
+        if let Err(error) = run(&source, &mut program) {
+            println!("{}", error.render(&source));
+        }
+    }
+}
+
+// Counts unbalanced `[`/`]` (OpenLambda/CloseLambda) and `(`/`)` tokens so
+// the REPL can tell a finished entry from one that's still missing its
+// closing bracket. A tokenize failure (e.g. a stray character) is treated
+// as balanced so the bad entry is handed to the parser/tokenizer proper,
+// where it can be reported with a span instead of hanging the prompt.
+fn brackets_balanced(source: &str) -> bool {
+    let tokens = match tokenize(source.to_string()) {
+        Ok(tokens) => tokens,
+        Err(_) => return true,
+    };
+
+    let mut depth = 0i32;
+    for token in tokens {
+        match token.kind {
+            TokenKind::OpenLambda | TokenKind::OpenParen => depth += 1,
+            TokenKind::CloseLambda | TokenKind::CloseParen => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0
 }
@@ -0,0 +1,89 @@
+//! An embedding API: lets host Rust code register native callables that
+//! Lithium scripts can send messages to like any other method, without
+//! editing `Number`/`Lambda` or any other of the interpreter's core types.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use object::{Object, ObjectPool, ObjectRef, EvaluatedMessage, SendOutcome};
+use error::{LithiumError, ErrorKind};
+use parser::Span;
+
+pub type NativeFn = Rc<dyn Fn(&mut ObjectPool, &[ObjectRef]) -> ObjectRef>;
+
+/// Host-registered Rust callables, bucketed by arity - arity-0, arity-1,
+/// arity-2, ... - so a name can be registered more than once for
+/// different argument counts without one registration shadowing another.
+#[derive(Default)]
+pub struct NativeRegistry {
+    arities: Vec<HashMap<String, NativeFn>>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> NativeRegistry {
+        NativeRegistry { arities: Vec::new() }
+    }
+
+    pub fn register<F>(&mut self, name: &str, arity: usize, handler: F)
+        where F: Fn(&mut ObjectPool, &[ObjectRef]) -> ObjectRef + 'static
+    {
+        while self.arities.len() <= arity {
+            self.arities.push(HashMap::new());
+        }
+        self.arities[arity].insert(name.to_string(), Rc::new(handler));
+    }
+
+    fn lookup(&self, name: &str, arity: usize) -> Option<NativeFn> {
+        self.arities.get(arity).and_then(|bucket| bucket.get(name)).cloned()
+    }
+}
+
+/// Sits at the base of every scope's prototype chain so that host-registered
+/// functions are reachable as ordinary sends, the same way `Number`'s
+/// built-in methods are - sending an unregistered name/arity pair yields
+/// an `UnknownMessage` error rather than a panic.
+pub struct NativeObject {
+    registry: Rc<RefCell<NativeRegistry>>,
+}
+
+impl NativeObject {
+    pub fn new(registry: Rc<RefCell<NativeRegistry>>) -> NativeObject {
+        NativeObject { registry }
+    }
+}
+
+// The registry holds `Rc<dyn Fn(...)>` handlers, which have no useful `Debug`
+// representation - print the object's identity instead of trying to derive
+// through them.
+impl fmt::Debug for NativeObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("NativeObject")
+    }
+}
+
+impl Object for NativeObject {
+    fn send(&mut self, pool: &mut ObjectPool, _target: ObjectRef, message: EvaluatedMessage) -> Result<SendOutcome, LithiumError> {
+        let handler = self.registry.borrow().lookup(&message.name, message.arguments.len());
+        match handler {
+            Some(handler) => {
+                let arguments: Vec<ObjectRef> = message.arguments.iter().map(|&(_, value)| value).collect();
+                Ok(SendOutcome::Value(handler(pool, &arguments)))
+            }
+            None => Err(LithiumError::new(ErrorKind::UnknownMessage { name: message.name.clone() }, message.span))
+        }
+    }
+
+    fn define(&mut self, _name: String, _value: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(
+            ErrorKind::TypeMismatch { expected: "an object that can be extended (a native object cannot)".to_string() },
+            Span::start()
+        ))
+    }
+
+    // Sits at the bottom of every prototype chain, so reaching here means
+    // `set` walked all the way up without finding an existing binding.
+    fn set(&mut self, _pool: &mut ObjectPool, name: String, _value: ObjectRef, span: Span) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(ErrorKind::UndeclaredVariable { name }, span))
+    }
+}
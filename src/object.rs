@@ -1,90 +1,305 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
 use std::fmt::Debug;
 
+use error::{ErrorKind, LithiumError};
+use parser::Span;
+
+/// What handling a message produced: either the final result, or a request
+/// to re-dispatch `message` to a different receiver now that this object is
+/// done with its turn. `ObjectPool::send` puts the current object back
+/// before following a `Delegate`, rather than recursing into the next send
+/// while still holding it - otherwise a delegation chain that loops back
+/// through an object still handling its own message (a lambda reading a
+/// variable from its own defining scope, one function calling another, ...)
+/// would find that object still checked out and unable to help.
+#[derive(Debug)]
+pub enum SendOutcome {
+    Value(ObjectRef),
+    Delegate(ObjectRef, EvaluatedMessage),
+}
+
+/// Something that can receive a message and have names bound directly on
+/// it via `def`. Every value in a running program - numbers, lambdas,
+/// scopes - is an `Object` living in an `ObjectPool`.
 pub trait Object: Debug {
-    fn send(&mut self, pool: &mut ObjectPool, target: ObjectRef, message: EvaluatedMessage) -> ObjectRef;
-    fn define(&mut self, name: String, value: ObjectRef) -> ObjectRef;
+    fn send(&mut self, pool: &mut ObjectPool, target: ObjectRef, message: EvaluatedMessage) -> Result<SendOutcome, LithiumError>;
+    fn define(&mut self, name: String, value: ObjectRef) -> Result<ObjectRef, LithiumError>;
+
+    // Mutates the nearest existing binding named `name`, walking up the
+    // prototype chain rather than always shadowing locally like `define`
+    // does. `pool` is threaded through so an implementation that doesn't
+    // hold the binding itself (`NormalObject`) can recurse into its
+    // prototype; one that can never hold bindings (`Number`, `Lambda`, ...)
+    // just reports the assignment as undeclared. `span` is only used to
+    // locate that error.
+    fn set(&mut self, pool: &mut ObjectPool, name: String, value: ObjectRef, span: Span) -> Result<ObjectRef, LithiumError>;
 }
 
-// A Message where all the expressions for the passed parametser
-// have been evaluated into object refrences.
-#[derive(Debug)]
+// A Message where all the expressions for the passed parameters
+// have been evaluated into object references.
+#[derive(Debug, Clone)]
 pub struct EvaluatedMessage {
-    name: String,
-    arguments: Vec<(String, ObjectRef)>
+    pub name: String,
+    pub arguments: Vec<(String, ObjectRef)>,
+    pub span: Span,
+}
+
+impl EvaluatedMessage {
+    pub fn argument(&self, name: &str) -> Option<ObjectRef> {
+        self.arguments.iter()
+            .find(|(arg_name, _)| arg_name == name)
+            .map(|&(_, value)| value)
+    }
 }
 
 #[derive(Debug)]
 pub struct NormalObject {
     prototype: ObjectRef,
     properties: HashMap<String, ObjectRef>,
-    metadata: Metadata
 }
 
 impl NormalObject {
-    fn extending(prototype: ObjectRef) -> NormalObject {
-        NormalObject{
-            prototype: prototype,
-            methods: HashMap::new(),
-            properties: HashMap::new()
+    pub fn extending(prototype: ObjectRef) -> NormalObject {
+        NormalObject {
+            prototype,
+            properties: HashMap::new(),
+        }
+    }
+}
+
+impl Object for NormalObject {
+    fn send(&mut self, _pool: &mut ObjectPool, _target: ObjectRef, message: EvaluatedMessage) -> Result<SendOutcome, LithiumError> {
+        match self.properties.get(&message.name).cloned() {
+            // Uniform access: a bound slot is resolved by "call"ing it, so a
+            // stored lambda behaves like a method while a stored value just
+            // hands itself back (see `Object::send` on `Number`/`Void`).
+            Some(bound) => {
+                let call = EvaluatedMessage { name: "call".to_string(), arguments: Vec::new(), span: message.span };
+                Ok(SendOutcome::Delegate(bound, call))
+            }
+            None => Ok(SendOutcome::Delegate(self.prototype, message))
+        }
+    }
+
+    fn define(&mut self, name: String, value: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        self.properties.insert(name, value);
+        Ok(value)
+    }
+
+    fn set(&mut self, pool: &mut ObjectPool, name: String, value: ObjectRef, span: Span) -> Result<ObjectRef, LithiumError> {
+        use std::collections::hash_map::Entry;
+        match self.properties.entry(name) {
+            Entry::Occupied(mut slot) => {
+                slot.insert(value);
+                Ok(value)
+            }
+            Entry::Vacant(slot) => pool.set(self.prototype, slot.into_key(), value, span)
         }
     }
 }
 
-fn get_handler(target: Object) {
+/// The numeric tower backing `Number`. Integer arithmetic stays exact;
+/// a division that doesn't come out even produces a `Rational` (always
+/// kept reduced to lowest terms, with a denominator greater than one -
+/// `reduce` collapses anything that divides evenly back to `Integer`);
+/// mixing in a `Float` anywhere contaminates the result to a float.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NumberValue {
+    Integer(i64),
+    Rational(i64, i64),
+    Float(f64),
 }
 
-impl NormalObject {
-    fn get_handler(&mut self, ObjectRef, message: EvaluatedMessage) -> ObjectRef {
-        match self.properties.get_mut(&message.name) {
-            Some(defined_object) => {
-                let clone = defined_object.clone();
-                let message = EvaluatedMessage {name: "call".into(), arguments: Vec::new()};
-                defined_object.send(clone, message)
+impl NumberValue {
+    // Parses a tokenizer `Number` literal: plain digits for an integer,
+    // `<digits>.<digits>` for a float, `<digits>/<digits>` for a rational.
+    // The tokenizer only ever produces one of these three shapes, so a
+    // malformed literal here would mean the lexer and parser disagree - but
+    // a literal can still be syntactically fine and simply too big for an
+    // `i64`, which is reported as an ordinary `LithiumError` rather than
+    // taking the process down.
+    pub fn parse(literal: &str, span: Span) -> Result<NumberValue, LithiumError> {
+        let out_of_range = || LithiumError::new(ErrorKind::NumberOutOfRange { literal: literal.to_string() }, span);
+        if literal.contains('.') {
+            Ok(NumberValue::Float(literal.parse().expect("malformed float literal")))
+        } else if let Some(slash) = literal.find('/') {
+            let numerator = literal[..slash].parse().map_err(|_| out_of_range())?;
+            let denominator = literal[slash + 1..].parse().map_err(|_| out_of_range())?;
+            if denominator == 0 {
+                return Err(LithiumError::new(ErrorKind::DivideByZero, span));
             }
-            None => self.prototype.send(target, message)
+            Ok(NumberValue::reduce(numerator, denominator))
+        } else {
+            let integer = literal.parse().map_err(|_| out_of_range())?;
+            Ok(NumberValue::Integer(integer))
         }
     }
 
-    fn define(&mut self, name: String, value: ObjectRef) -> ObjectRef {
-        self.properties.insert(name, value.clone());
-        value.clone()
+    // Reduces a fraction to lowest terms with a positive denominator,
+    // collapsing back to `Integer` when the denominator divides evenly.
+    pub fn reduce(numerator: i64, denominator: i64) -> NumberValue {
+        NumberValue::reduce_wide(numerator as i128, denominator as i128)
+    }
+
+    // Same as `reduce`, but takes its components as `i128` - `eval::arithmetic`
+    // cross-multiplies a pair of `i64` fractions before reducing, which can
+    // briefly need more than 64 bits of headroom even though the reduced
+    // result almost always fits back in an `i64`. Falls back to `Float` on
+    // the rare case it doesn't (e.g. two large, mutually prime operands).
+    pub(crate) fn reduce_wide(numerator: i128, denominator: i128) -> NumberValue {
+        let (numerator, denominator) = if denominator < 0 {
+            (-numerator, -denominator)
+        } else {
+            (numerator, denominator)
+        };
+        let divisor = gcd128(numerator.abs(), denominator).max(1);
+        let (numerator, denominator) = (numerator / divisor, denominator / divisor);
+        match (i64::try_from(numerator), i64::try_from(denominator)) {
+            (Ok(numerator), Ok(1)) => NumberValue::Integer(numerator),
+            (Ok(numerator), Ok(denominator)) => NumberValue::Rational(numerator, denominator),
+            _ => NumberValue::Float(numerator as f64 / denominator as f64),
+        }
+    }
+
+    // `(numerator, denominator)` for the exact (non-float) members of the
+    // tower, so arithmetic can cross-multiply instead of converting
+    // through a lossy float. Panics on `Float` - callers branch on that
+    // variant first since mixing in a float always takes the float path.
+    pub fn as_fraction(self) -> (i64, i64) {
+        match self {
+            NumberValue::Integer(n) => (n, 1),
+            NumberValue::Rational(n, d) => (n, d),
+            NumberValue::Float(_) => unreachable!("as_fraction called on a float"),
+        }
+    }
+
+    pub fn as_f64(self) -> f64 {
+        match self {
+            NumberValue::Integer(n) => n as f64,
+            NumberValue::Rational(n, d) => n as f64 / d as f64,
+            NumberValue::Float(f) => f,
+        }
+    }
+
+    pub fn is_zero(self) -> bool {
+        match self {
+            NumberValue::Integer(n) => n == 0,
+            NumberValue::Rational(n, _) => n == 0,
+            NumberValue::Float(f) => f == 0.0,
+        }
+    }
+}
+
+impl fmt::Display for NumberValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NumberValue::Integer(n) => write!(f, "{}", n),
+            NumberValue::Rational(n, d) => write!(f, "{}/{}", n, d),
+            NumberValue::Float(x) => write!(f, "{}", x),
+        }
     }
 }
 
+fn gcd128(a: i128, b: i128) -> i128 {
+    if b == 0 { a } else { gcd128(b, a % b) }
+}
 
 #[derive(Debug, Clone)]
 pub enum Metadata {
-    NumericValue(i64),
+    NumericValue(NumberValue),
+    BooleanValue(bool),
     None
 }
 
+/// An arena of every live object. Objects are addressed by `ObjectRef`
+/// rather than owned directly so that scopes, prototypes and captured
+/// lambdas can all refer to the same instance.
 pub struct ObjectPool {
-    normal_objects: Vec<NormalObject>,
-    special_objects: Vec<Box<Object>>
+    objects: Vec<Option<Box<dyn Object>>>,
+    metadata: Vec<Metadata>,
+    native: Option<ObjectRef>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ObjectRef {
-    Normal(usize),
-    Special(usize)
-}
+pub struct ObjectRef(usize);
 
 impl ObjectPool {
-    fn create(&mut self, prototype: ObjectRef) -> ObjectRef {
-        let object = NormalObject::extending(prototype);
-        self.normal_objects.push(object);
-        ObjectRef::Normal(self.normal_objects.len() - 1)
+    pub fn new() -> ObjectPool {
+        ObjectPool { objects: Vec::new(), metadata: Vec::new(), native: None }
+    }
+
+    // Registers the object every value type falls back to for a message it
+    // doesn't itself recognize - see `delegate_to_native`. Set once, right
+    // after `Program::new` inserts its `NativeObject`.
+    pub fn set_native(&mut self, native: ObjectRef) {
+        self.native = Some(native);
     }
 
-    // Returns the callable object that will handle a response
-    fn send(&mut self, reference: ObjectRef, message: EvaluatedMessage) -> ObjectRef {
-        match reference {
-            ObjectRef::Normal(index) => self.normal_objects[index].send(self, message),
-            ObjectRef::Special(index) => self.special_objects[index].send(self, message)
+    fn native(&self) -> ObjectRef {
+        self.native.expect("delegated to the native object before one was registered")
+    }
+
+    // An unknown-message fallback for value types (`Number`, `Boolean`,
+    // lambdas, ...): forwards the message to the native registry, offering
+    // `target` up as an implicit receiver argument ahead of the message's
+    // own named arguments. This is what makes `register_fn("sqrt", 1, ...)`
+    // reachable as `5 sqrt` - the registered arity counts the receiver, the
+    // same way `Number#add`'s receiver plus its `to:` argument make two
+    // operands even though the message itself only carries one.
+    pub fn delegate_to_native(&self, target: ObjectRef, message: EvaluatedMessage) -> SendOutcome {
+        let mut arguments = Vec::with_capacity(message.arguments.len() + 1);
+        arguments.push(("self".to_string(), target));
+        arguments.extend(message.arguments);
+        SendOutcome::Delegate(self.native(), EvaluatedMessage { name: message.name, arguments, span: message.span })
+    }
+
+    pub fn insert(&mut self, object: Box<dyn Object>) -> ObjectRef {
+        self.insert_with_metadata(object, Metadata::None)
+    }
+
+    pub fn insert_with_metadata(&mut self, object: Box<dyn Object>, metadata: Metadata) -> ObjectRef {
+        self.objects.push(Some(object));
+        self.metadata.push(metadata);
+        ObjectRef(self.objects.len() - 1)
+    }
+
+    pub fn metadata(&self, reference: ObjectRef) -> &Metadata {
+        &self.metadata[reference.0]
+    }
+
+    // Takes the target out of the pool for the duration of its own turn so
+    // its handler can freely send other messages without aliasing its own
+    // `&mut`. The object goes back into the pool as soon as that turn ends -
+    // before a `Delegate` outcome is followed - so a delegation chain that
+    // loops back through it (a lambda reading a variable from its own
+    // defining scope, one function calling another, ...) finds it available
+    // rather than still checked out.
+    pub fn send(&mut self, target: ObjectRef, message: EvaluatedMessage) -> Result<ObjectRef, LithiumError> {
+        let mut object = self.objects[target.0].take()
+            .expect("send to an object that is already handling a message");
+        let outcome = object.send(self, target, message);
+        self.objects[target.0] = Some(object);
+        match outcome? {
+            SendOutcome::Value(value) => Ok(value),
+            SendOutcome::Delegate(next_target, next_message) => self.send(next_target, next_message),
         }
     }
 
-    fn define(&mut self, reference: ObjectRef, name: String, value: ObjectRef) -> ObjectRef {
+    pub fn define(&mut self, target: ObjectRef, name: String, value: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        let mut object = self.objects[target.0].take()
+            .expect("define on an object that is already handling a message");
+        let result = object.define(name, value);
+        self.objects[target.0] = Some(object);
+        result
     }
-}
\ No newline at end of file
+
+    pub fn set(&mut self, target: ObjectRef, name: String, value: ObjectRef, span: Span) -> Result<ObjectRef, LithiumError> {
+        let mut object = self.objects[target.0].take()
+            .expect("set on an object that is already handling a message");
+        let result = object.set(self, name, value, span);
+        self.objects[target.0] = Some(object);
+        result
+    }
+}
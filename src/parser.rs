@@ -2,10 +2,29 @@ use std::iter::Peekable;
 use std::vec;
 use std::str::Chars;
 
+use error::{LithiumError, ErrorKind};
+
+/// A position in the source text, threaded through every token so that a
+/// later failure (an unknown message, a malformed signature, ...) can be
+/// reported against the line it came from instead of just crashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn start() -> Span {
+        Span { offset: 0, line: 1, column: 1 }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Message {
     pub name: String,
     pub arguments: Vec<Argument>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -32,12 +51,13 @@ pub enum Target {
 pub struct Send {
     pub target: Target,
     pub messages: Vec<Message>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub enum Expression {
     Send(Send),
-    Number(String),
+    Number(String, Span),
 
     // NOTE: Heap allocation :(
     Lambda(Box<Block>),
@@ -46,22 +66,35 @@ pub enum Expression {
 #[derive(Debug, Clone)]
 pub struct Definition {
     pub target: String,
-    pub value: Expression
+    pub value: Expression,
+    pub span: Span,
+}
+
+// `set <identifier> <expression>` - unlike `Definition`, this mutates the
+// nearest existing binding up the scope chain rather than always
+// shadowing locally; see `ObjectPool::set`.
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub target: String,
+    pub value: Expression,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub enum Statement{
     Expression(Expression),
-    Definition(Definition)
+    Definition(Definition),
+    Assignment(Assignment)
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub enum Token {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
     Identifier(String),
     Number(String),
     ParamName(String),
     NextStatement,
     Def,
+    Set,
     Then,
     OpenParen,
     CloseParen,
@@ -69,145 +102,293 @@ pub enum Token {
     CloseLambda
 }
 
-type Tokens = Peekable<vec::IntoIter<Token>>;
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+// Wraps the raw token iterator so every production can blame the end of
+// input on the last token it actually managed to consume, instead of some
+// arbitrary zero position.
+struct Tokens {
+    inner: Peekable<vec::IntoIter<Token>>,
+    last_span: Span,
+}
+
+impl Tokens {
+    fn new(tokens: Vec<Token>) -> Tokens {
+        Tokens { inner: tokens.into_iter().peekable(), last_span: Span::start() }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.inner.next();
+        if let Some(ref token) = token {
+            self.last_span = token.span;
+        }
+        token
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.inner.peek()
+    }
+
+    fn eof_span(&self) -> Span {
+        self.last_span
+    }
+}
+
+pub fn parse_program(tokens: Vec<Token>) -> Result<Block, LithiumError> {
+    let mut tokens = Tokens::new(tokens);
+    parse_block(&mut tokens)
+}
 
-pub fn parse_program(tokens: Vec<Token>) -> Block {
-    let mut token_iter = tokens.into_iter().peekable();
-    parse_block(&mut token_iter)
+// Consumes a run of zero or more `NextStatement` tokens - blank lines are
+// insignificant wherever a statement could start, not just between two
+// statements that are actually there.
+fn skip_blank_lines(tokens: &mut Tokens) {
+    while let Some(&TokenKind::NextStatement) = tokens.peek().map(|token| &token.kind) {
+        tokens.next();
+    }
 }
 
-fn parse_block(tokens: &mut Tokens) -> Block {
+fn parse_block(tokens: &mut Tokens) -> Result<Block, LithiumError> {
     let mut block = Block{statements: Vec::new()};
 
-    while tokens.peek().is_some() {
-        block.statements.push(parse_statement(tokens));
-        match tokens.peek() {
-            Some(&Token::NextStatement) => { tokens.next(); },
-            Some(&Token::CloseLambda) => break,
+    // A block's contents can start with a blank line - typing a lambda body
+    // across several lines (the REPL's whole point) means pressing Enter
+    // right after the opening '[', which leaves a leading `NextStatement`
+    // token before any statement has been parsed at all.
+    skip_blank_lines(tokens);
+
+    while tokens.peek().map(|token| &token.kind) != Some(&TokenKind::CloseLambda) && tokens.peek().is_some() {
+        block.statements.push(parse_statement(tokens)?);
+        match tokens.peek().map(|token| &token.kind) {
+            Some(&TokenKind::NextStatement) => {
+                tokens.next();
+                skip_blank_lines(tokens);
+            },
+            Some(&TokenKind::CloseLambda) => break,
             None => (),
-            _ => panic!("Unknown remaining tokens after parsing statement")
+            _ => {
+                let token = tokens.next().expect("peek just returned Some");
+                return Err(unexpected(&token));
+            }
         }
     }
 
-    return block;
+    Ok(block)
 }
 
-fn parse_statement(tokens: &mut Tokens) -> Statement {
+fn parse_statement(tokens: &mut Tokens) -> Result<Statement, LithiumError> {
     // <subject|identifier> <message|identifier>
-    match tokens.peek().cloned() {
-        Some(Token::Def) => Statement::Definition(parse_definition(tokens)),
-        Some(_) => Statement::Expression(parse_expression(tokens)),
-        None => panic!("Ran out of tokens in statement D:")
+    match tokens.peek().map(|token| &token.kind) {
+        Some(&TokenKind::Def) => parse_definition(tokens).map(Statement::Definition),
+        Some(&TokenKind::Set) => parse_assignment(tokens).map(Statement::Assignment),
+        Some(_) => parse_expression(tokens).map(Statement::Expression),
+        None => Err(unexpected_eof(tokens))
     }
 }
 
-fn parse_definition(tokens: &mut Tokens) -> Definition {
+fn parse_definition(tokens: &mut Tokens) -> Result<Definition, LithiumError> {
     // def <identifier> <expression>
-    match (tokens.next(), tokens.next()) {
-        (Some(Token::Def), Some(Token::Identifier(identifier))) => {
-            Definition {
+    let def_token = tokens.next().expect("parse_definition called without a leading 'def'");
+    match tokens.next() {
+        Some(Token { kind: TokenKind::Identifier(identifier), .. }) => {
+            Ok(Definition {
                 target: identifier,
-                value: parse_expression(tokens)
-            }
+                value: parse_expression(tokens)?,
+                span: def_token.span,
+            })
+        },
+        Some(token) => Err(unexpected(&token)),
+        None => Err(unexpected_eof(tokens))
+    }
+}
+
+fn parse_assignment(tokens: &mut Tokens) -> Result<Assignment, LithiumError> {
+    // set <identifier> <expression>
+    let set_token = tokens.next().expect("parse_assignment called without a leading 'set'");
+    match tokens.next() {
+        Some(Token { kind: TokenKind::Identifier(identifier), .. }) => {
+            Ok(Assignment {
+                target: identifier,
+                value: parse_expression(tokens)?,
+                span: set_token.span,
+            })
         },
-        _ => panic!("Uh oh, malformed definition")
+        Some(token) => Err(unexpected(&token)),
+        None => Err(unexpected_eof(tokens))
     }
 }
 
-fn parse_expression(tokens: &mut Tokens) -> Expression {
-    match (tokens.next(), tokens.peek()) {
-        (Some(Token::OpenLambda), _) => {
-            let lambda = Expression::Lambda(Box::new(parse_block(tokens)));
+fn parse_expression(tokens: &mut Tokens) -> Result<Expression, LithiumError> {
+    let expression = parse_primary_expression(tokens)?;
+    // <expression> then ifTrue: [...] ifFalse: [...]
+    match tokens.peek().map(|token| &token.kind) {
+        Some(&TokenKind::Then) => {
+            let then_token = tokens.next().expect("peek just returned Some");
+            Ok(Expression::Send(Send {
+                target: Target::Expression(Box::new(expression)),
+                span: then_token.span,
+                messages: vec![
+                    Message { name: "then".to_string(), arguments: parse_send_arguments(tokens)?, span: then_token.span }
+                ]
+            }))
+        },
+        _ => Ok(expression)
+    }
+}
+
+fn parse_primary_expression(tokens: &mut Tokens) -> Result<Expression, LithiumError> {
+    let token = tokens.next().ok_or_else(|| unexpected_eof(tokens))?;
+    let span = token.span;
+
+    match token.kind {
+        TokenKind::OpenLambda => {
+            let lambda = Expression::Lambda(Box::new(parse_block(tokens)?));
             match tokens.next() {
-                Some(Token::CloseLambda) => (),
-                _ => panic!("Expected lambda to end with a closing bracket")
+                Some(Token { kind: TokenKind::CloseLambda, .. }) => Ok(lambda),
+                Some(other) => Err(LithiumError::new(ErrorKind::UnterminatedLambda, other.span)),
+                None => Err(LithiumError::new(ErrorKind::UnterminatedLambda, tokens.eof_span()))
             }
-            lambda
         },
         // (myCar start) println
-        (Some(Token::OpenParen), _) => {
-            let subject = parse_expression(tokens);
+        TokenKind::OpenParen => {
+            let subject = parse_expression(tokens)?;
             tokens.next(); // Remove the remaining ')'
-            match tokens.peek() {
+            match tokens.peek().map(|token| &token.kind) {
                 // There is a message being sent to the result
                 // DUPE from below. Extract this parsing logic?
-                Some(&Token::Identifier(_)) => {
-                    let message = match tokens.next() {
-                        Some(Token::Identifier(message)) => message,
-                        _ => unreachable!()
-                    };
-                    Expression::Send(Send{
+                Some(&TokenKind::Identifier(_)) => {
+                    let message = parse_message(tokens)?;
+                    Ok(Expression::Send(Send {
                         target: Target::Expression(Box::new(subject)),
-                        messages: vec![
-                            Message{name: message, arguments: parse_send_arguments(tokens)}
-                        ]
-                    })
+                        span,
+                        messages: vec![message]
+                    }))
                 },
-                _ => subject
+                _ => Ok(subject)
             }
         },
         // myCar start
-        (Some(Token::Identifier(subject)), Some(&Token::Identifier(_))) => {
-            let message = match tokens.next() {
-                Some(Token::Identifier(message)) => message,
-                _ => unreachable!()
-            };
-            Expression::Send(Send{
-                target: Target::Identifier(subject),
-                messages: vec![
-                    Message{name: message, arguments: parse_send_arguments(tokens)}
-                ]
-            })
+        TokenKind::Identifier(subject) => {
+            match tokens.peek().map(|token| &token.kind) {
+                Some(&TokenKind::Identifier(_)) => {
+                    let message = parse_message(tokens)?;
+                    Ok(Expression::Send(Send {
+                        target: Target::Identifier(subject),
+                        span,
+                        messages: vec![message]
+                    }))
+                },
+                _ => Err(LithiumError::new(
+                    ErrorKind::UnexpectedToken { found: format!("identifier '{}'", subject) },
+                    span
+                ))
+            }
         },
         // 123 println
-        (Some(Token::Number(num)), Some(&Token::Identifier(_))) => {
-            let message = match tokens.next() {
-                Some(Token::Identifier(message)) => message,
-                _ => unreachable!()
-            };
-            Expression::Send(Send{
-                target: Target::Number(num),
-                messages: vec![
-                    Message{name: message, arguments: parse_send_arguments(tokens)}
-                ]
-            })
+        TokenKind::Number(num) => {
+            match tokens.peek().map(|token| &token.kind) {
+                Some(&TokenKind::Identifier(_)) => {
+                    let message = parse_message(tokens)?;
+                    Ok(Expression::Send(Send {
+                        target: Target::Number(num),
+                        span,
+                        messages: vec![message]
+                    }))
+                },
+                _ => Ok(Expression::Number(num, span))
+            }
         },
-        (Some(Token::Number(num)), _) => Expression::Number(num),
-        (None, None) => panic!("Uh oh, ran out of tokens in expression"),
-        _ => panic!("Unhandled tokens")
+        other => Err(LithiumError::new(ErrorKind::UnexpectedToken { found: describe(&other) }, span))
     }
 }
 
-fn parse_send_arguments(tokens: &mut Tokens) -> Vec<Argument> {
+fn parse_message(tokens: &mut Tokens) -> Result<Message, LithiumError> {
+    let message_token = tokens.next().expect("parse_message called without a following identifier");
+    let name = match message_token.kind {
+        TokenKind::Identifier(name) => name,
+        _ => unreachable!()
+    };
+    Ok(Message { name, arguments: parse_send_arguments(tokens)?, span: message_token.span })
+}
+
+fn parse_send_arguments(tokens: &mut Tokens) -> Result<Vec<Argument>, LithiumError> {
     let mut params = Vec::new();
-    while let Some(Token::ParamName(name)) = tokens.peek().cloned() {
-        tokens.next();
+    while let Some(&TokenKind::ParamName(_)) = tokens.peek().map(|token| &token.kind) {
+        let name = match tokens.next().expect("peek just returned Some").kind {
+            TokenKind::ParamName(name) => name,
+            _ => unreachable!()
+        };
         params.push(Argument{
-            name: name,
-            value: parse_expression(tokens)
+            name,
+            value: parse_expression(tokens)?
         });
     }
-    return params
+    Ok(params)
 }
 
-pub fn tokenize(code: String) -> Vec<Token> {
+fn unexpected(token: &Token) -> LithiumError {
+    LithiumError::new(ErrorKind::UnexpectedToken { found: describe(&token.kind) }, token.span)
+}
+
+fn unexpected_eof(tokens: &Tokens) -> LithiumError {
+    LithiumError::new(ErrorKind::UnexpectedToken { found: "end of input".to_string() }, tokens.eof_span())
+}
+
+fn describe(kind: &TokenKind) -> String {
+    match *kind {
+        TokenKind::Identifier(ref name) => format!("identifier '{}'", name),
+        TokenKind::Number(ref digits) => format!("number '{}'", digits),
+        TokenKind::ParamName(ref name) => format!("parameter '{}:'", name),
+        TokenKind::NextStatement => "newline".to_string(),
+        TokenKind::Def => "'def'".to_string(),
+        TokenKind::Set => "'set'".to_string(),
+        TokenKind::Then => "'then'".to_string(),
+        TokenKind::OpenParen => "'('".to_string(),
+        TokenKind::CloseParen => "')'".to_string(),
+        TokenKind::OpenLambda => "'['".to_string(),
+        TokenKind::CloseLambda => "']'".to_string(),
+    }
+}
+
+pub fn tokenize(code: String) -> Result<Vec<Token>, LithiumError> {
     let mut tokens = Vec::new();
-    let mut chars = code.chars().into_iter().peekable();
+    let mut chars = code.chars().peekable();
+    let mut cursor = Span::start();
+
     while let Some(c) = chars.next() {
+        let start = cursor;
+        advance(&mut cursor, c);
+
         if c != '\n' && c.is_whitespace() { continue }
-        tokens.push(match c {
-            '\n' => Token::NextStatement,
-            '(' => Token::OpenParen,
-            ')' => Token::CloseParen,
-            '[' => Token::OpenLambda,
-            ']' => Token::CloseLambda,
-            _ => get_long_token(c.clone(), &mut chars)
-        });
+
+        let kind = match c {
+            '\n' => TokenKind::NextStatement,
+            '(' => TokenKind::OpenParen,
+            ')' => TokenKind::CloseParen,
+            '[' => TokenKind::OpenLambda,
+            ']' => TokenKind::CloseLambda,
+            _ => get_long_token(c, &mut chars, start, &mut cursor)?
+        };
+        tokens.push(Token { kind, span: start });
+    }
+    Ok(tokens)
+}
+
+fn advance(cursor: &mut Span, c: char) {
+    cursor.offset += c.len_utf8();
+    if c == '\n' {
+        cursor.line += 1;
+        cursor.column = 1;
+    } else {
+        cursor.column += 1;
     }
-    return tokens;
 }
 
-fn get_long_token(prev: char, chars: &mut Peekable<Chars>) -> Token {
+fn get_long_token(prev: char, chars: &mut Peekable<Chars>, start: Span, cursor: &mut Span) -> Result<TokenKind, LithiumError> {
     if prev.is_alphabetic() {
         let mut name = String::new();
         name.push(prev);
@@ -215,27 +396,59 @@ fn get_long_token(prev: char, chars: &mut Peekable<Chars>) -> Token {
             if !c.is_alphabetic() {
                 if c == ':' {
                     chars.next();
-                    return Token::ParamName(name);
+                    advance(cursor, ':');
+                    return Ok(TokenKind::ParamName(name));
                 }
                 break;
             }
-            name.push(chars.next().unwrap())
+            chars.next();
+            advance(cursor, c);
+            name.push(c)
         }
         // Keywords
-        match name {
-            ref s if s == "def" => Token::Def,
-            ref s if s == "then" => Token::Then,
-            _ => Token::Identifier(name)
-        }
+        Ok(match name {
+            ref s if s == "def" => TokenKind::Def,
+            ref s if s == "set" => TokenKind::Set,
+            ref s if s == "then" => TokenKind::Then,
+            _ => TokenKind::Identifier(name)
+        })
     } else if prev.is_numeric() {
         let mut number = String::new();
         number.push(prev);
         while let Some(c) = chars.peek().cloned() {
             if !c.is_numeric() { break }
-            number.push(chars.next().unwrap())
+            chars.next();
+            advance(cursor, c);
+            number.push(c)
         }
-        Token::Number(number)
+        // 3.14 - a float literal
+        if chars.peek() == Some(&'.') {
+            number.push('.');
+            chars.next();
+            advance(cursor, '.');
+            while let Some(c) = chars.peek().cloned() {
+                if !c.is_numeric() { break }
+                chars.next();
+                advance(cursor, c);
+                number.push(c)
+            }
+        // 1/2 - a rational literal
+        } else if chars.peek() == Some(&'/') {
+            number.push('/');
+            chars.next();
+            advance(cursor, '/');
+            while let Some(c) = chars.peek().cloned() {
+                if !c.is_numeric() { break }
+                chars.next();
+                advance(cursor, c);
+                number.push(c)
+            }
+        }
+        Ok(TokenKind::Number(number))
     } else {
-        unreachable!()
+        Err(LithiumError::new(
+            ErrorKind::UnexpectedToken { found: format!("character '{}'", prev) },
+            start
+        ))
     }
-}
\ No newline at end of file
+}
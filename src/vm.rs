@@ -0,0 +1,402 @@
+//! A bytecode compiler and stack-based VM that lowers a parsed `Block` into
+//! a flat instruction stream once, rather than re-walking the AST on every
+//! run. `eval::Program::eval` is the normal entry point; the tree-walking
+//! `eval::Program::eval_tree` remains as a fallback / reference
+//! implementation the VM is modeled on.
+use std::rc::Rc;
+
+use parser::{Block, Statement, Expression, Target, Message, Span};
+use object::{Object, ObjectPool, ObjectRef, EvaluatedMessage, NormalObject, NumberValue, SendOutcome};
+use eval::{Number, Void};
+use error::{LithiumError, ErrorKind};
+
+// Maps an operand's declared `kind` (see `define_instructions!`) to the
+// primitive writer that encodes it as little-endian bytes.
+macro_rules! write_operand {
+    (usize, $out:expr, $field:expr) => { write_usize($out, *$field) };
+    (string, $out:expr, $field:expr) => { write_string($out, $field) };
+    (span, $out:expr, $field:expr) => { write_span($out, *$field) };
+    (number, $out:expr, $field:expr) => { write_number($out, *$field) };
+    (strings, $out:expr, $field:expr) => {{
+        write_usize($out, $field.len());
+        for item in $field {
+            write_string($out, item);
+        }
+    }};
+}
+
+// The paired reader for each `write_operand!` kind.
+macro_rules! read_operand {
+    (usize, $code:expr, $pos:expr) => { read_usize($code, $pos) };
+    (string, $code:expr, $pos:expr) => { read_string($code, $pos) };
+    (span, $code:expr, $pos:expr) => { read_span($code, $pos) };
+    (number, $code:expr, $pos:expr) => { read_number($code, $pos) };
+    (strings, $code:expr, $pos:expr) => {{
+        let len = read_usize($code, $pos);
+        let mut items = Vec::with_capacity(len);
+        for _ in 0..len {
+            items.push(read_string($code, $pos));
+        }
+        items
+    }};
+}
+
+// Declares the bytecode format: each opcode's tag byte, its `Instruction`
+// variant, and the little-endian encoding of its operands (see
+// `write_operand!`/`read_operand!`). `Instruction::write` and
+// `Instruction::read` are both generated from this single list, so adding
+// or reordering an opcode can't leave the encoder and decoder out of sync
+// the way two hand-written functions could.
+macro_rules! define_instructions {
+    ($($tag:ident = $value:expr => $variant:ident $( { $($field:ident : $kind:ident),* $(,)? } )?),* $(,)?) => {
+        mod opcode {
+            $(pub const $tag: u8 = $value;)*
+        }
+
+        impl Instruction {
+            fn write(&self, out: &mut Vec<u8>) {
+                match *self {
+                    $(
+                        Instruction::$variant $( { $(ref $field),* } )? => {
+                            out.push(opcode::$tag);
+                            $( $( write_operand!($kind, out, $field); )* )?
+                        }
+                    )*
+                }
+            }
+
+            fn read(code: &[u8], pos: &mut usize) -> Instruction {
+                let tag = code[*pos];
+                *pos += 1;
+                match tag {
+                    $(
+                        opcode::$tag => Instruction::$variant $( {
+                            $( $field: read_operand!($kind, code, pos) ),*
+                        } )?,
+                    )*
+                    _ => unreachable!("unknown opcode byte {}", tag),
+                }
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    PushNumber { value: NumberValue },
+    PushLambda { chunk_id: usize },
+    Load { name: String, span: Span },
+    Define { name: String },
+    Assign { name: String, span: Span },
+    Send { name: String, arg_names: Vec<String>, span: Span },
+    Pop,
+}
+
+define_instructions! {
+    PUSH_NUMBER = 0 => PushNumber { value: number },
+    PUSH_LAMBDA = 1 => PushLambda { chunk_id: usize },
+    LOAD = 2 => Load { name: string, span: span },
+    DEFINE = 3 => Define { name: string },
+    SEND = 4 => Send { name: string, arg_names: strings, span: span },
+    POP = 5 => Pop,
+    ASSIGN = 6 => Assign { name: string, span: span },
+}
+
+fn write_usize(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+fn write_i64(out: &mut Vec<u8>, value: i64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_f64(out: &mut Vec<u8>, value: f64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+mod number_tag {
+    pub const INTEGER: u8 = 0;
+    pub const RATIONAL: u8 = 1;
+    pub const FLOAT: u8 = 2;
+}
+
+fn write_number(out: &mut Vec<u8>, value: NumberValue) {
+    match value {
+        NumberValue::Integer(n) => {
+            out.push(number_tag::INTEGER);
+            write_i64(out, n);
+        }
+        NumberValue::Rational(numerator, denominator) => {
+            out.push(number_tag::RATIONAL);
+            write_i64(out, numerator);
+            write_i64(out, denominator);
+        }
+        NumberValue::Float(value) => {
+            out.push(number_tag::FLOAT);
+            write_f64(out, value);
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    write_usize(out, value.len());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn write_span(out: &mut Vec<u8>, span: Span) {
+    write_usize(out, span.offset);
+    write_usize(out, span.line);
+    write_usize(out, span.column);
+}
+
+fn read_usize(code: &[u8], pos: &mut usize) -> usize {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&code[*pos..*pos + 8]);
+    *pos += 8;
+    u64::from_le_bytes(bytes) as usize
+}
+
+fn read_i64(code: &[u8], pos: &mut usize) -> i64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&code[*pos..*pos + 8]);
+    *pos += 8;
+    i64::from_le_bytes(bytes)
+}
+
+fn read_f64(code: &[u8], pos: &mut usize) -> f64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&code[*pos..*pos + 8]);
+    *pos += 8;
+    f64::from_le_bytes(bytes)
+}
+
+fn read_number(code: &[u8], pos: &mut usize) -> NumberValue {
+    let tag = code[*pos];
+    *pos += 1;
+    match tag {
+        number_tag::INTEGER => NumberValue::Integer(read_i64(code, pos)),
+        number_tag::RATIONAL => {
+            let numerator = read_i64(code, pos);
+            let denominator = read_i64(code, pos);
+            NumberValue::Rational(numerator, denominator)
+        }
+        number_tag::FLOAT => NumberValue::Float(read_f64(code, pos)),
+        _ => unreachable!("unknown number tag byte {}", tag),
+    }
+}
+
+fn read_string(code: &[u8], pos: &mut usize) -> String {
+    let len = read_usize(code, pos);
+    let bytes = code[*pos..*pos + len].to_vec();
+    *pos += len;
+    String::from_utf8(bytes).expect("bytecode contained invalid utf8")
+}
+
+fn read_span(code: &[u8], pos: &mut usize) -> Span {
+    Span {
+        offset: read_usize(code, pos),
+        line: read_usize(code, pos),
+        column: read_usize(code, pos),
+    }
+}
+
+/// One compiled lambda body: a flat instruction stream addressed by chunk
+/// index rather than inlined, mirroring how `Lambda` captures `parent_scope`
+/// instead of inlining its body at every call site.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    code: Vec<u8>,
+}
+
+impl Chunk {
+    fn push(&mut self, instruction: Instruction) {
+        instruction.write(&mut self.code);
+    }
+}
+
+/// Lowers a parsed `Block` into a flat chunk table. The entry block becomes
+/// chunk 0; each `Lambda` expression compiles to its own chunk, referenced
+/// by index from a `PushLambda` instruction in the enclosing chunk.
+pub struct Compiler {
+    chunks: Vec<Chunk>,
+}
+
+impl Compiler {
+    // Fallible because a `Number` literal is only validated here, at compile
+    // time - see `NumberValue::parse`.
+    pub fn compile(block: &Block) -> Result<Vec<Chunk>, LithiumError> {
+        let mut compiler = Compiler { chunks: vec![Chunk::default()] };
+        compiler.compile_block_into(0, block)?;
+        Ok(compiler.chunks)
+    }
+
+    fn compile_block_into(&mut self, chunk_id: usize, block: &Block) -> Result<(), LithiumError> {
+        let last_index = block.statements.len().checked_sub(1);
+        for (index, statement) in block.statements.iter().enumerate() {
+            self.compile_statement(chunk_id, statement)?;
+            if Some(index) != last_index {
+                self.chunks[chunk_id].push(Instruction::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_statement(&mut self, chunk_id: usize, statement: &Statement) -> Result<(), LithiumError> {
+        match *statement {
+            Statement::Definition(ref definition) => {
+                self.compile_expression(chunk_id, &definition.value)?;
+                self.chunks[chunk_id].push(Instruction::Define { name: definition.target.clone() });
+            }
+            Statement::Assignment(ref assignment) => {
+                self.compile_expression(chunk_id, &assignment.value)?;
+                self.chunks[chunk_id].push(Instruction::Assign { name: assignment.target.clone(), span: assignment.span });
+            }
+            Statement::Expression(ref expression) => self.compile_expression(chunk_id, expression)?,
+        }
+        Ok(())
+    }
+
+    fn compile_expression(&mut self, chunk_id: usize, expression: &Expression) -> Result<(), LithiumError> {
+        match *expression {
+            Expression::Number(ref literal, span) => {
+                self.chunks[chunk_id].push(Instruction::PushNumber { value: NumberValue::parse(literal, span)? });
+            }
+            Expression::Lambda(ref body) => {
+                let lambda_chunk_id = self.chunks.len();
+                self.chunks.push(Chunk::default());
+                self.compile_block_into(lambda_chunk_id, body)?;
+                self.chunks[chunk_id].push(Instruction::PushLambda { chunk_id: lambda_chunk_id });
+            }
+            Expression::Send(ref send) => {
+                self.compile_target(chunk_id, &send.target, send.span)?;
+                for message in &send.messages {
+                    self.compile_message(chunk_id, message)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_target(&mut self, chunk_id: usize, target: &Target, span: Span) -> Result<(), LithiumError> {
+        match *target {
+            Target::Number(ref literal) => {
+                self.chunks[chunk_id].push(Instruction::PushNumber { value: NumberValue::parse(literal, span)? });
+            }
+            Target::Identifier(ref name) => {
+                self.chunks[chunk_id].push(Instruction::Load { name: name.clone(), span });
+            }
+            Target::Expression(ref expression) => self.compile_expression(chunk_id, expression)?,
+        }
+        Ok(())
+    }
+
+    fn compile_message(&mut self, chunk_id: usize, message: &Message) -> Result<(), LithiumError> {
+        for argument in &message.arguments {
+            self.compile_expression(chunk_id, &argument.value)?;
+        }
+        self.chunks[chunk_id].push(Instruction::Send {
+            name: message.name.clone(),
+            arg_names: message.arguments.iter().map(|argument| argument.name.clone()).collect(),
+            span: message.span,
+        });
+        Ok(())
+    }
+}
+
+/// A lambda produced by the compiler. Unlike the tree-walking `Lambda`,
+/// which reinterprets a `Block` on every call, this replays a fixed
+/// instruction stream against a chunk table shared (via `Rc`) with every
+/// other lambda compiled from the same program.
+#[derive(Debug)]
+struct CompiledLambda {
+    parent_scope: ObjectRef,
+    chunk_id: usize,
+    chunks: Rc<Vec<Chunk>>,
+}
+
+impl Object for CompiledLambda {
+    fn send(&mut self, pool: &mut ObjectPool, target: ObjectRef, message: EvaluatedMessage) -> Result<SendOutcome, LithiumError> {
+        match message.name.as_str() {
+            "call" => Vm::new(pool, self.chunks.clone()).run(self.chunk_id, self.parent_scope).map(SendOutcome::Value),
+            // Falls back to any native function registered for this name,
+            // offering `target` up as the implicit receiver argument - see
+            // `ObjectPool::delegate_to_native`.
+            _ => Ok(pool.delegate_to_native(target, message))
+        }
+    }
+
+    fn define(&mut self, _name: String, _value: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(
+            ErrorKind::TypeMismatch { expected: "an object that can be extended (a lambda cannot)".to_string() },
+            Span::start()
+        ))
+    }
+
+    fn set(&mut self, _pool: &mut ObjectPool, name: String, _value: ObjectRef, span: Span) -> Result<ObjectRef, LithiumError> {
+        Err(LithiumError::new(ErrorKind::UndeclaredVariable { name }, span))
+    }
+}
+
+pub struct Vm<'a> {
+    pool: &'a mut ObjectPool,
+    chunks: Rc<Vec<Chunk>>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(pool: &'a mut ObjectPool, chunks: Rc<Vec<Chunk>>) -> Vm<'a> {
+        Vm { pool, chunks }
+    }
+
+    // `call` pushes a new frame whose locals are a `NormalObject` extending
+    // the captured scope, same as the tree-walking evaluator's blocks.
+    pub fn run(&mut self, chunk_id: usize, parent_scope: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        let scope = self.pool.insert(Box::new(NormalObject::extending(parent_scope)));
+        self.run_in_scope(chunk_id, scope)
+    }
+
+    pub fn run_in_scope(&mut self, chunk_id: usize, scope: ObjectRef) -> Result<ObjectRef, LithiumError> {
+        let code = self.chunks[chunk_id].code.clone();
+        let mut stack: Vec<ObjectRef> = Vec::new();
+        let mut pos = 0;
+
+        while pos < code.len() {
+            match Instruction::read(&code, &mut pos) {
+                Instruction::PushNumber { value } => {
+                    stack.push(Number::new_from_value(self.pool, value));
+                }
+                Instruction::PushLambda { chunk_id: lambda_chunk_id } => {
+                    let lambda = CompiledLambda {
+                        parent_scope: scope,
+                        chunk_id: lambda_chunk_id,
+                        chunks: self.chunks.clone(),
+                    };
+                    stack.push(self.pool.insert(Box::new(lambda)));
+                }
+                Instruction::Load { name, span } => {
+                    let message = EvaluatedMessage { name, arguments: Vec::new(), span };
+                    stack.push(self.pool.send(scope, message)?);
+                }
+                Instruction::Define { name } => {
+                    let value = stack.pop().expect("Define with no value on the stack");
+                    stack.push(self.pool.define(scope, name, value)?);
+                }
+                Instruction::Assign { name, span } => {
+                    let value = stack.pop().expect("Assign with no value on the stack");
+                    stack.push(self.pool.set(scope, name, value, span)?);
+                }
+                Instruction::Send { name, arg_names, span } => {
+                    let mut arguments: Vec<(String, ObjectRef)> = arg_names.into_iter().rev().map(|arg_name| {
+                        let value = stack.pop().expect("Send argument missing from the stack");
+                        (arg_name, value)
+                    }).collect();
+                    arguments.reverse();
+                    let target = stack.pop().expect("Send with no target on the stack");
+                    stack.push(self.pool.send(target, EvaluatedMessage { name, arguments, span })?);
+                }
+                Instruction::Pop => { stack.pop(); }
+            }
+        }
+
+        Ok(stack.pop().unwrap_or_else(|| Void::new_reference(self.pool)))
+    }
+}